@@ -0,0 +1,38 @@
+use kvs::{KvStore, KvsServer};
+use std::net::SocketAddr;
+use std::process::exit;
+use structopt::StructOpt;
+
+fn main() {
+    let opt: Opt = Opt::from_args();
+
+    let store: KvStore<String, String> =
+        match KvStore::open(std::env::current_dir().unwrap()) {
+            Ok(store) => store,
+            Err(error) => {
+                eprintln!("{}", error);
+                exit(1);
+            }
+        };
+
+    if let Err(error) = KvsServer::new(store).run(opt.addr) {
+        eprintln!("{}", error);
+        exit(1);
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = env!("CARGO_PKG_NAME"),
+    version = env!("CARGO_PKG_VERSION"),
+    author = env!("CARGO_PKG_AUTHORS"),
+    about = "Serve a KvStore over TCP"
+)]
+struct Opt {
+    #[structopt(
+        long,
+        default_value = "127.0.0.1:4000",
+        help = "Address to listen on"
+    )]
+    addr: SocketAddr,
+}
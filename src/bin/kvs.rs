@@ -1,29 +1,65 @@
-use kvs::{KvStore, KvStoreError};
+use kvs::{KvStore, KvStoreError, KvsClient, KvsEngine};
+use std::net::SocketAddr;
 use std::process::exit;
 use structopt::StructOpt;
 
 fn main() {
     let opt: Opt = Opt::from_args();
-    let mut store = KvStore::open(std::env::current_dir().unwrap()).unwrap();
     match opt.cmd {
-        Command::Get { key } => {
-            if let Some(value) = store.get(key).unwrap() {
-                println!("{}", value);
-            } else {
-                println!("Key not found");
-            }
-        }
-        Command::Set { key, value } => {
-            store.set(key, value).unwrap();
-        }
-        Command::Remove { key } => {
-            if let Err(error) = store.remove(key) {
-                if let KvStoreError::KeyNotFound { key: _ } = error {
+        Command::Get { key, addr } => match addr {
+            Some(addr) => match KvsClient::get(addr, key) {
+                Ok(Some(value)) => println!("{}", value),
+                Ok(None) => println!("Key not found"),
+                Err(error) => {
+                    eprintln!("{}", error);
+                    exit(1);
+                }
+            },
+            None => {
+                let mut store: KvStore<String, String> =
+                    KvStore::open(std::env::current_dir().unwrap()).unwrap();
+                if let Some(value) = store.get(key).unwrap() {
+                    println!("{}", value);
+                } else {
                     println!("Key not found");
                 }
-                exit(1);
-            };
-        }
+            }
+        },
+        Command::Set { key, value, addr } => match addr {
+            Some(addr) => {
+                if let Err(error) = KvsClient::set(addr, key, value) {
+                    eprintln!("{}", error);
+                    exit(1);
+                }
+            }
+            None => {
+                let mut store: KvStore<String, String> =
+                    KvStore::open(std::env::current_dir().unwrap()).unwrap();
+                store.set(key, value).unwrap();
+            }
+        },
+        Command::Remove { key, addr } => match addr {
+            Some(addr) => {
+                if let Err(error) = KvsClient::remove(addr, key) {
+                    if let KvStoreError::KeyNotFound { key: _ } = error {
+                        println!("Key not found");
+                    } else {
+                        eprintln!("{}", error);
+                    }
+                    exit(1);
+                }
+            }
+            None => {
+                let mut store: KvStore<String, String> =
+                    KvStore::open(std::env::current_dir().unwrap()).unwrap();
+                if let Err(error) = store.remove(key) {
+                    if let KvStoreError::KeyNotFound { key: _ } = error {
+                        println!("Key not found");
+                    }
+                    exit(1);
+                };
+            }
+        },
     }
 }
 
@@ -42,9 +78,22 @@ struct Opt {
 #[derive(Debug, StructOpt)]
 enum Command {
     #[structopt(name = "set", about = "Set <value> in <key>")]
-    Set { key: String, value: String },
+    Set {
+        key: String,
+        value: String,
+        #[structopt(long, help = "Connect to a kvs-server at this address instead of the local store")]
+        addr: Option<SocketAddr>,
+    },
     #[structopt(name = "get", about = "Get value in <key>")]
-    Get { key: String },
+    Get {
+        key: String,
+        #[structopt(long, help = "Connect to a kvs-server at this address instead of the local store")]
+        addr: Option<SocketAddr>,
+    },
     #[structopt(name = "rm", about = "Remove <key>")]
-    Remove { key: String },
+    Remove {
+        key: String,
+        #[structopt(long, help = "Connect to a kvs-server at this address instead of the local store")]
+        addr: Option<SocketAddr>,
+    },
 }
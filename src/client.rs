@@ -0,0 +1,62 @@
+//! TCP client for talking to a `kvs-server`
+
+use crate::common::{read_message, write_message, Request, Response};
+use crate::{KvStoreError, Result};
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// A client for a `kvs-server`
+///
+/// Each call opens a fresh connection, sends one request, reads one response, and closes the
+/// connection again, mirroring how the local `KvStore` API is used one call at a time.
+pub struct KvsClient;
+
+impl KvsClient {
+    /// Store `value` under `key` on the server listening at `addr`
+    pub fn set(addr: impl ToSocketAddrs, key: String, value: String) -> Result<()> {
+        match Self::send(addr, Request::Set { key, value })? {
+            Response::Ok => Ok(()),
+            Response::Err(msg) => Err(response_error(msg)),
+            Response::Value(_) => Err(unexpected_response("set")),
+        }
+    }
+
+    /// Look up `key` on the server listening at `addr`
+    pub fn get(addr: impl ToSocketAddrs, key: String) -> Result<Option<String>> {
+        match Self::send(addr, Request::Get { key })? {
+            Response::Value(value) => Ok(value),
+            Response::Err(msg) => Err(response_error(msg)),
+            Response::Ok => Err(unexpected_response("get")),
+        }
+    }
+
+    /// Remove `key` on the server listening at `addr`
+    pub fn remove(addr: impl ToSocketAddrs, key: String) -> Result<()> {
+        match Self::send(addr, Request::Remove { key })? {
+            Response::Ok => Ok(()),
+            Response::Err(msg) => Err(response_error(msg)),
+            Response::Value(_) => Err(unexpected_response("remove")),
+        }
+    }
+
+    fn send(addr: impl ToSocketAddrs, request: Request) -> Result<Response> {
+        let mut stream = TcpStream::connect(addr)?;
+        write_message(&mut stream, &request)?;
+        read_message(&mut stream)
+    }
+}
+
+fn unexpected_response(op: &str) -> KvStoreError {
+    KvStoreError::UnknownError {
+        msg: format!("unexpected response to {}", op),
+    }
+}
+
+/// Recover a `KvStoreError::KeyNotFound` from its formatted message where possible, so a failed
+/// `remove` over the network behaves like a failed local `remove`; anything else becomes a plain
+/// `UnknownError` carrying the server's message.
+fn response_error(msg: String) -> KvStoreError {
+    match msg.strip_prefix("key not found: ") {
+        Some(key) => KvStoreError::KeyNotFound { key: key.to_owned() },
+        None => KvStoreError::UnknownError { msg },
+    }
+}
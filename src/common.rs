@@ -0,0 +1,70 @@
+//! Wire protocol shared between `kvs-server` and the `--addr`-routed client commands
+//!
+//! Every message, in either direction, is framed the same way the on-disk log is: a
+//! little-endian `u64` length prefix followed by that many bytes of `bincode`-serialized data.
+
+use crate::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// A single request sent from a client to a `kvs-server`
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Request {
+    /// Store `value` under `key`
+    Set {
+        /// The key to store
+        key: String,
+        /// The value to associate with the key
+        value: String,
+    },
+    /// Look up the value stored under `key`
+    Get {
+        /// The key to look up
+        key: String,
+    },
+    /// Remove `key` and its value
+    Remove {
+        /// The key to remove
+        key: String,
+    },
+}
+
+/// A server's response to a single `Request`
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Response {
+    /// The result of a `Get`: the value, if the key was present
+    Value(Option<String>),
+    /// A successful `Set` or `Remove`
+    Ok,
+    /// The request failed; carries `error.to_string()` from the `KvStoreError` that caused it
+    Err(String),
+}
+
+/// Write `message` to `writer` as a length-prefixed `bincode` record
+pub(crate) fn write_message<W, T>(writer: &mut W, message: &T) -> Result<()>
+where
+    W: Write,
+    T: Serialize,
+{
+    let payload = bincode::serialize(message)?;
+    writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read a single length-prefixed `bincode` record from `reader`
+pub(crate) fn read_message<R, T>(reader: &mut R) -> Result<T>
+where
+    R: Read,
+    T: DeserializeOwned,
+{
+    let mut len_buf = [0u8; 8];
+    reader.read_exact(&mut len_buf)?;
+    let len = u64::from_le_bytes(len_buf) as usize;
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(bincode::deserialize(&payload)?)
+}
@@ -0,0 +1,25 @@
+//! Pluggable storage engine trait
+
+use crate::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::hash::Hash;
+
+/// A key/value storage backend that can be set, queried and mutated the same way regardless of how
+/// it persists data underneath. `KvStore` is the one implementation in this crate today; the trait
+/// exists so callers like [`crate::KvsServer`] can be written against an abstract engine and
+/// swapped to an alternative backend later without changing their call sites.
+pub trait KvsEngine<K, V>
+where
+    K: Serialize + DeserializeOwned + Eq + Hash + Clone,
+    V: Serialize + DeserializeOwned,
+{
+    /// Store `value` under `key`, overwriting any existing value
+    fn set(&mut self, key: K, value: V) -> Result<()>;
+
+    /// Look up the value stored under `key`
+    fn get(&mut self, key: K) -> Result<Option<V>>;
+
+    /// Remove `key` and its value; fails with [`crate::KvStoreError::KeyNotFound`] if it is absent
+    fn remove(&mut self, key: K) -> Result<()>;
+}
@@ -1,11 +1,29 @@
 #![deny(missing_docs)]
 //! KvStore lib code
 
+mod client;
+mod common;
+mod engine;
+mod server;
+
+pub use client::KvsClient;
+pub use engine::KvsEngine;
+pub use server::KvsServer;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Seek, SeekFrom, Write, BufWriter};
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hash;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use failure::Fail;
 
 /// KvStore result type
@@ -42,6 +60,34 @@ pub enum KvStoreError {
         /// Message from the error
         msg: String
     },
+
+    /// Decrypting a log record failed, either because the passphrase was wrong or the record was
+    /// corrupted: the AEAD authentication tag did not match
+    #[fail(display = "failed to decrypt record: authentication tag mismatch")]
+    DecryptError,
+
+    /// `open` was called on a directory that was already initialized by a different storage
+    /// engine
+    #[fail(
+        display = "{} already contains a {} engine's data, cannot reopen it as {}",
+        path, found, expected
+    )]
+    EngineMismatch {
+        /// Directory that was opened
+        path: String,
+        /// Engine name recorded in the directory's marker file
+        found: String,
+        /// Engine name the current `open` call is trying to use
+        expected: String,
+    },
+
+    /// `open` was called without a passphrase on a directory that already holds an encryption
+    /// header from an earlier `open` with one
+    #[fail(display = "{} was encrypted with a passphrase; open it with the same passphrase", path)]
+    PassphraseRequired {
+        /// Directory that was opened
+        path: String,
+    },
 }
 
 impl From<std::io::Error> for KvStoreError {
@@ -52,8 +98,8 @@ impl From<std::io::Error> for KvStoreError {
     }
 }
 
-impl From<ron::Error> for KvStoreError {
-    fn from(error: ron::Error) -> Self {
+impl From<bincode::Error> for KvStoreError {
+    fn from(error: bincode::Error) -> Self {
         KvStoreError::CommandConvertError {
             msg: error.to_string(),
         }
@@ -61,63 +107,601 @@ impl From<ron::Error> for KvStoreError {
 }
 
 #[derive(Serialize, Deserialize)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+enum Command<K, V> {
+    Set { key: K, value: V },
+    Remove { key: K },
+}
+
+/// Once a single log segment grows past this many bytes, writes roll over to a new segment
+const SEGMENT_SIZE_THRESHOLD: u64 = 1024 * 1024;
+
+/// Once the bytes occupied by stale (overwritten or removed) entries passes this many bytes,
+/// the next write triggers a compaction
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// Number of bytes used to store a record's length prefix
+const LEN_PREFIX_SIZE: u64 = 8;
+
+/// Name of the index snapshot sidecar file in the store's directory
+const INDEX_FILE_NAME: &str = ".kvs_index";
+
+/// Name of the sidecar file holding the encryption header (cipher byte + salt), if encryption is
+/// in use
+const HEADER_FILE_NAME: &str = ".kvs_header";
+
+/// Name of the sidecar file recording which storage engine a directory was initialized with
+const ENGINE_FILE_NAME: &str = ".kvs_engine";
+
+/// Identifies `KvStore`'s on-disk format in a directory's engine marker file
+const ENGINE_NAME: &str = "kvs";
+
+/// Bytes of random salt used to derive the encryption key from a passphrase
+const SALT_LEN: usize = 16;
+
+/// Bytes of random nonce prepended to each encrypted record
+const NONCE_LEN: usize = 12;
+
+/// One-byte tag identifying which AEAD cipher a store's records are encrypted with, stored in the
+/// header so the on-disk format is self-describing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CipherSuite {
+    ChaCha20Poly1305 = 0,
+}
+
+impl CipherSuite {
+    fn from_byte(byte: u8) -> Result<CipherSuite> {
+        match byte {
+            0 => Ok(CipherSuite::ChaCha20Poly1305),
+            other => Err(KvStoreError::UnknownError {
+                msg: format!("unsupported cipher suite byte {}", other),
+            }),
+        }
+    }
+}
+
+/// Derived encryption key plus the cipher it was derived for; encrypts/decrypts individual log
+/// records with a fresh random nonce per record
+#[derive(Clone)]
+struct CipherState {
+    cipher: ChaCha20Poly1305,
+}
+
+impl CipherState {
+    /// Derive a 256-bit key from `passphrase` and `salt` with Argon2 and build the cipher for it
+    fn derive(passphrase: &str, salt: &[u8; SALT_LEN]) -> Result<CipherState> {
+        let mut key_bytes = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key_bytes)
+            .map_err(|error| KvStoreError::UnknownError {
+                msg: format!("key derivation failed: {}", error),
+            })?;
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key_bytes));
+        Ok(CipherState { cipher })
+    }
+
+    /// Encrypt `plaintext` with a fresh random nonce, returning `nonce || ciphertext || tag`
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce_bytes);
+
+        let mut sealed = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce_bytes), plaintext)
+            .map_err(|_| KvStoreError::DecryptError)?;
+        let mut record = nonce_bytes.to_vec();
+        record.append(&mut sealed);
+        Ok(record)
+    }
+
+    /// Split a `nonce || ciphertext || tag` record and decrypt it, authenticating the tag
+    fn open(&self, record: &[u8]) -> Result<Vec<u8>> {
+        if record.len() < NONCE_LEN {
+            return Err(KvStoreError::DecryptError);
+        }
+        let (nonce_bytes, ciphertext) = record.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce_bytes), ciphertext)
+            .map_err(|_| KvStoreError::DecryptError)
+    }
+}
+
+/// Points at a single command record inside one of the store's log segments
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct LogPointer {
+    segment_id: u64,
+    offset: u64,
+    len: u64,
+}
+
+/// On-disk snapshot of the in-memory index, written on compaction and clean shutdown so the next
+/// `open` can skip replaying the whole log
+#[derive(Serialize, Deserialize)]
+#[serde(bound(serialize = "K: Serialize", deserialize = "K: Eq + Hash + DeserializeOwned"))]
+struct IndexSnapshot<K> {
+    /// Version counter, bumped every time a snapshot is written. It carries over from a loaded
+    /// snapshot; when `open` falls back to a full log replay it restarts from 0.
+    generation: u64,
+    /// Active segment at the time the snapshot was taken
+    active_segment: u64,
+    /// Length of the active segment at the time the snapshot was taken; anything appended after
+    /// this point still needs to be replayed
+    active_segment_len: u64,
+    uncompacted: u64,
+    map: HashMap<K, LogPointer>,
+}
+
+/// Controls how aggressively a `KvStore` flushes writes to disk
+#[derive(Debug, Clone, Copy)]
+pub enum SyncPolicy {
+    /// Leave durability to the OS page cache; writes can be lost on a crash, but nothing blocks
+    /// on disk I/O
+    Never,
+    /// `fsync` the active segment after every `set`/`remove`; slowest, but nothing is ever lost
+    EveryWrite,
+    /// `fsync` at most once per interval, batching any writes that land in between
+    Interval(Duration),
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy::Never
+    }
+}
+
+/// Builder for opening a `KvStore` with non-default options
+pub struct KvStoreOptions<K, V> {
+    sync_policy: SyncPolicy,
+    passphrase: Option<String>,
+    _key: PhantomData<K>,
+    _value: PhantomData<V>,
+}
+
+impl<K, V> KvStoreOptions<K, V>
+where
+    K: Serialize + DeserializeOwned + Eq + Hash + Clone,
+    V: Serialize + DeserializeOwned,
+{
+    /// Start building a `KvStore` with default options
+    pub fn new() -> Self {
+        KvStoreOptions {
+            sync_policy: SyncPolicy::default(),
+            passphrase: None,
+            _key: PhantomData,
+            _value: PhantomData,
+        }
+    }
+
+    /// Set the durability policy used for writes
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Encrypt every record at rest with a key derived from `passphrase`. The first `open` for a
+    /// directory picks a random salt and records it (plus the cipher in use) in a small header
+    /// file; later opens reuse that salt, so the same passphrase must be supplied every time.
+    pub fn passphrase(mut self, passphrase: impl Into<String>) -> Self {
+        self.passphrase = Some(passphrase.into());
+        self
+    }
+
+    /// Open the store at `path` with the configured options
+    pub fn open(self, path: impl Into<PathBuf>) -> Result<KvStore<K, V>> {
+        KvStore::open_with_options(path, self)
+    }
+}
+
+impl<K, V> Default for KvStoreOptions<K, V>
+where
+    K: Serialize + DeserializeOwned + Eq + Hash + Clone,
+    V: Serialize + DeserializeOwned,
+{
+    fn default() -> Self {
+        KvStoreOptions::new()
+    }
 }
 
-/// Used to create a representation of a key-value store
-pub struct KvStore {
-    file_handle: File,
-    map: HashMap<String, u64>,
+/// The crate's log-structured [`KvsEngine`] implementation
+///
+/// Data is kept as an append-only sequence of log segment files (`1.log`, `2.log`, ...) in the
+/// store's directory, bitcask-style. Each record is written as a little-endian `u64` length
+/// prefix followed by that many bytes of a `bincode`-serialized `Command` (sealed as
+/// `nonce || ciphertext || tag` when the store is opened with a passphrase), so values containing
+/// arbitrary bytes (including newlines) round-trip correctly. An in-memory index maps each key to
+/// the segment, offset and length of its most recent command, so reads never need to scan the
+/// log. `open` records `"kvs"` as this directory's engine in a marker file, so a later attempt to
+/// open the same directory with a different `KvsEngine` fails instead of corrupting the data.
+pub struct KvStore<K, V>
+where
+    K: Serialize + DeserializeOwned + Eq + Hash + Clone,
+    V: Serialize + DeserializeOwned,
+{
+    dir: PathBuf,
+    map: HashMap<K, LogPointer>,
+    readers: HashMap<u64, BufReader<File>>,
+    writer: BufWriter<File>,
+    current_segment: u64,
     is_build: bool,
-    count_of_set: u64,
+    uncompacted: u64,
+    generation: u64,
+    sync_policy: SyncPolicy,
+    last_sync: Option<Instant>,
+    cipher: Option<CipherState>,
+    _value: PhantomData<V>,
 }
 
-impl KvStore {
-    /// Open the KvStore with the given dir path
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let mut path: PathBuf = path.into();
-        path.push(".kvs_store");
-        let file_handle = OpenOptions::new()
+impl<K, V> KvStore<K, V>
+where
+    K: Serialize + DeserializeOwned + Eq + Hash + Clone,
+    V: Serialize + DeserializeOwned,
+{
+    /// Open the KvStore at the given directory, creating it and an initial segment if needed
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore<K, V>> {
+        KvStoreOptions::default().open(path)
+    }
+
+    /// Open the KvStore at the given directory using a pre-built [`KvStoreOptions`]
+    fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions<K, V>) -> Result<KvStore<K, V>> {
+        let dir: PathBuf = path.into();
+        fs::create_dir_all(&dir)?;
+        check_engine_marker(&dir, ENGINE_NAME)?;
+
+        let cipher = match &options.passphrase {
+            Some(passphrase) => Some(open_cipher(&dir, passphrase)?),
+            None if header_path(&dir).exists() => {
+                // The directory already holds an encryption header from an earlier `open` with a
+                // passphrase; opening it unencrypted would silently append plaintext records into
+                // a log that otherwise holds only ciphertext.
+                return Err(KvStoreError::PassphraseRequired {
+                    path: dir.display().to_string(),
+                });
+            }
+            None => None,
+        };
+
+        let segment_ids = sorted_segment_ids(&dir)?;
+        let current_segment = segment_ids.last().copied().unwrap_or(1);
+
+        let mut readers = HashMap::new();
+        for &segment_id in &segment_ids {
+            if segment_id == current_segment {
+                continue;
+            }
+            let file = OpenOptions::new().read(true).open(segment_path(&dir, segment_id))?;
+            readers.insert(segment_id, BufReader::new(file));
+        }
+
+        let writer_file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(path)?;
+            .open(segment_path(&dir, current_segment))?;
+        readers.insert(current_segment, BufReader::new(writer_file.try_clone()?));
+        let writer = BufWriter::new(writer_file);
 
-        Ok(KvStore {
-            file_handle,
+        let mut store = KvStore {
+            dir,
             map: HashMap::new(),
+            readers,
+            writer,
+            current_segment,
             is_build: false,
-            count_of_set: 0,
+            uncompacted: 0,
+            generation: 0,
+            sync_policy: options.sync_policy,
+            last_sync: None,
+            cipher,
+            _value: PhantomData,
+        };
+
+        if let Some(snapshot) = load_index_snapshot::<K>(&store.dir, current_segment) {
+            let tail_start = snapshot.active_segment_len;
+            store.map = snapshot.map;
+            store.uncompacted = snapshot.uncompacted;
+            store.generation = snapshot.generation;
+            store.is_build = true;
+            store.replay_segment_from(current_segment, tail_start)?;
+        }
+
+        Ok(store)
+    }
+
+    /// Serialize `cmd` as a length-prefixed record, append it to the active segment and return
+    /// where it landed
+    fn append(&mut self, cmd: &Command<K, V>) -> Result<LogPointer> {
+        let plaintext = bincode::serialize(cmd)?;
+        let payload = match &self.cipher {
+            Some(cipher) => cipher.seal(&plaintext)?,
+            None => plaintext,
+        };
+
+        let offset = self.writer.seek(SeekFrom::End(0))?;
+        self.writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&payload)?;
+        self.writer.flush()?;
+
+        Ok(LogPointer {
+            segment_id: self.current_segment,
+            offset,
+            len: LEN_PREFIX_SIZE + payload.len() as u64,
         })
     }
 
+    fn fetch_value(&mut self, pointer: LogPointer) -> Result<V> {
+        let payload = read_record_payload(&mut self.readers, pointer)?;
+        let plaintext = decrypt_bytes(self.cipher.as_ref(), &payload)?;
+        match bincode::deserialize::<Command<K, V>>(&plaintext)? {
+            Command::Set { key: _, value } => Ok(value),
+            Command::Remove { .. } => Err(KvStoreError::UnknownError {
+                msg: "Command info not matched".to_owned(),
+            }),
+        }
+    }
+
+    fn build_map(&mut self) -> Result<()> {
+        if self.is_build {
+            return Ok(());
+        }
+        self.map.clear();
+        self.uncompacted = 0;
+
+        let mut segment_ids: Vec<u64> = self.readers.keys().copied().collect();
+        segment_ids.sort_unstable();
+
+        for segment_id in segment_ids {
+            self.replay_segment_from(segment_id, 0)?;
+        }
+
+        self.is_build = true;
+        Ok(())
+    }
+
+    /// Replay every command record in `segment_id` starting at `start_offset`, folding it into
+    /// `self.map`/`self.uncompacted`. Used both for a full log replay (`start_offset` 0) and for
+    /// catching up the tail written after an index snapshot was taken.
+    fn replay_segment_from(&mut self, segment_id: u64, start_offset: u64) -> Result<()> {
+        let cipher = self.cipher.clone();
+        let dir = self.dir.clone();
+        let reader = self
+            .readers
+            .get_mut(&segment_id)
+            .expect("segment reader missing");
+        let mut cur_offset = reader.seek(SeekFrom::Start(start_offset))?;
+
+        loop {
+            let mut len_buf = [0u8; LEN_PREFIX_SIZE as usize];
+            match reader.read_exact(&mut len_buf) {
+                Ok(()) => {}
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(error) => return Err(error.into()),
+            }
+            let payload_len = u64::from_le_bytes(len_buf);
+            let mut payload = vec![0u8; payload_len as usize];
+            match reader.read_exact(&mut payload) {
+                Ok(()) => {}
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                    // A crash mid-append can leave a complete length prefix with a truncated
+                    // payload, the classic torn write. Drop that dangling partial record instead
+                    // of failing every future `open` of this directory.
+                    truncate_segment(&dir, segment_id, cur_offset)?;
+                    break;
+                }
+                Err(error) => return Err(error.into()),
+            }
+            let record_len = LEN_PREFIX_SIZE + payload_len;
+
+            let plaintext = decrypt_bytes(cipher.as_ref(), &payload)?;
+            let cmd: Command<K, V> = bincode::deserialize(&plaintext)?;
+            match cmd {
+                Command::Set { key, value: _ } => {
+                    let pointer = LogPointer {
+                        segment_id,
+                        offset: cur_offset,
+                        len: record_len,
+                    };
+                    if let Some(old) = self.map.insert(key, pointer) {
+                        self.uncompacted += old.len;
+                    }
+                }
+                Command::Remove { key } => {
+                    if let Some(old) = self.map.remove(&key) {
+                        self.uncompacted += old.len;
+                    }
+                    self.uncompacted += record_len;
+                }
+            }
+            cur_offset += record_len;
+        }
+
+        Ok(())
+    }
+
+    /// Serialize the current index to the `.kvs_index` sidecar file so the next `open` can skip
+    /// straight to it instead of replaying the whole log
+    fn save_index_snapshot(&mut self) -> Result<()> {
+        // `self.map` only reflects reality once the log has actually been replayed (or loaded from
+        // a prior snapshot); skipping this would let a store that was opened and dropped without
+        // any `get`/`set`/`remove` overwrite a good snapshot (or write a fresh one) with an empty
+        // map, hiding every key already on disk from the next `open`.
+        self.build_map()?;
+
+        self.writer.flush()?;
+        let active_segment_len = self.writer.seek(SeekFrom::End(0))?;
+        self.generation += 1;
+
+        let snapshot = IndexSnapshot {
+            generation: self.generation,
+            active_segment: self.current_segment,
+            active_segment_len,
+            uncompacted: self.uncompacted,
+            map: self.map.clone(),
+        };
+        let bytes = bincode::serialize(&snapshot)?;
+        fs::write(index_path(&self.dir), bytes)?;
+
+        Ok(())
+    }
+
+    /// Honor the configured `SyncPolicy` after a write, fsyncing the active segment if the policy
+    /// calls for it
+    fn maybe_sync(&mut self) -> Result<()> {
+        let should_sync = match self.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::Interval(interval) => {
+                let now = Instant::now();
+                self.last_sync.map_or(true, |last| now.duration_since(last) >= interval)
+            }
+        };
+
+        if !should_sync {
+            return Ok(());
+        }
+
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        self.last_sync = Some(Instant::now());
+
+        Ok(())
+    }
+
+    /// Roll over to a fresh active segment once the current one crosses the size threshold
+    fn maybe_roll_segment(&mut self) -> Result<()> {
+        let segment_len = self.writer.seek(SeekFrom::End(0))?;
+        if segment_len <= SEGMENT_SIZE_THRESHOLD {
+            return Ok(());
+        }
+
+        self.writer.flush()?;
+        let finished_segment = self.current_segment;
+        let finished_reader = OpenOptions::new().read(true).open(segment_path(&self.dir, finished_segment))?;
+        self.readers.insert(finished_segment, BufReader::new(finished_reader));
+
+        self.current_segment += 1;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(segment_path(&self.dir, self.current_segment))?;
+        self.readers.insert(self.current_segment, BufReader::new(file.try_clone()?));
+        self.writer = BufWriter::new(file);
+
+        Ok(())
+    }
+
+    /// Compact once enough of the log is dead weight, keeping compaction cost proportional to
+    /// live data instead of total log size
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.uncompacted > COMPACTION_THRESHOLD {
+            self.compaction()?;
+        }
+        Ok(())
+    }
+
+    fn compaction(&mut self) -> Result<()> {
+        self.build_map()?;
+
+        let compaction_segment = self.current_segment + 1;
+        let new_active_segment = self.current_segment + 2;
+
+        let mut compaction_writer = BufWriter::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(segment_path(&self.dir, compaction_segment))?,
+        );
+
+        let mut new_map = HashMap::with_capacity(self.map.len());
+        let mut offset = 0u64;
+        let entries: Vec<(K, LogPointer)> =
+            self.map.iter().map(|(key, &pointer)| (key.clone(), pointer)).collect();
+        for (key, pointer) in entries {
+            let value = self.fetch_value(pointer)?;
+            let cmd = Command::Set { key: key.clone(), value };
+            let plaintext = bincode::serialize(&cmd)?;
+            let payload = match &self.cipher {
+                Some(cipher) => cipher.seal(&plaintext)?,
+                None => plaintext,
+            };
+            let record_len = LEN_PREFIX_SIZE + payload.len() as u64;
+
+            compaction_writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+            compaction_writer.write_all(&payload)?;
+            new_map.insert(
+                key,
+                LogPointer {
+                    segment_id: compaction_segment,
+                    offset,
+                    len: record_len,
+                },
+            );
+            offset += record_len;
+        }
+        compaction_writer.flush()?;
+        if !matches!(self.sync_policy, SyncPolicy::Never) {
+            // The stale segments are deleted below, so their live data must reach disk first
+            compaction_writer.get_ref().sync_all()?;
+        }
+
+        let stale_segments: Vec<u64> = self.readers.keys().copied().collect();
+
+        let compaction_reader = OpenOptions::new().read(true).open(segment_path(&self.dir, compaction_segment))?;
+        let new_active_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(segment_path(&self.dir, new_active_segment))?;
+        let new_active_reader = new_active_file.try_clone()?;
+
+        self.readers.clear();
+        self.readers.insert(compaction_segment, BufReader::new(compaction_reader));
+        self.readers.insert(new_active_segment, BufReader::new(new_active_reader));
+        self.writer = BufWriter::new(new_active_file);
+        self.current_segment = new_active_segment;
+        self.map = new_map;
+        self.uncompacted = 0;
+
+        for segment_id in stale_segments {
+            fs::remove_file(segment_path(&self.dir, segment_id))?;
+        }
+
+        self.save_index_snapshot()?;
+
+        Ok(())
+    }
+}
+
+impl<K, V> KvsEngine<K, V> for KvStore<K, V>
+where
+    K: Serialize + DeserializeOwned + Eq + Hash + Clone,
+    V: Serialize + DeserializeOwned,
+{
     /// Store value with the key
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+    fn set(&mut self, key: K, value: V) -> Result<()> {
+        self.build_map()?;
+
         let cmd = Command::Set {
             key: key.clone(),
             value,
         };
-        let mut cmd = ron::to_string(&cmd)?;
-        cmd.push('\n');
-        let offset = self.file_handle.seek(SeekFrom::End(0))?;
-        self.file_handle.write_all(cmd.as_bytes())?;
-        self.map.insert(key, offset);
-        self.count_of_set += 1;
-        if self.count_of_set > 100 {
-            self.compaction()?;
-            self.count_of_set = 0;
+        let pointer = self.append(&cmd)?;
+        if let Some(old) = self.map.insert(key, pointer) {
+            self.uncompacted += old.len;
         }
+        self.maybe_sync()?;
+
+        self.maybe_roll_segment()?;
+        self.maybe_compact()?;
 
         Ok(())
     }
 
     /// Get the value from the given key
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+    fn get(&mut self, key: K) -> Result<Option<V>> {
         self.build_map()?;
-        if let Some(&offset) = self.map.get(&key) {
-            let value = self.fetch_value(offset)?;
+        if let Some(&pointer) = self.map.get(&key) {
+            let value = self.fetch_value(pointer)?;
             Ok(Some(value))
         } else {
             Ok(None)
@@ -125,83 +709,191 @@ impl KvStore {
     }
 
     /// Remove the given key
-    pub fn remove(&mut self, key: String) -> Result<()> {
+    fn remove(&mut self, key: K) -> Result<()> {
         self.build_map()?;
-        if !self.map.contains_key(&key) {
-            return Err(KvStoreError::KeyNotFound { key });
-        }
-        let cmd = Command::Remove { key: key.clone() };
-        let mut cmd = ron::to_string(&cmd)?;
-        cmd.push('\n');
-        self.file_handle.seek(SeekFrom::End(0))?;
-        self.file_handle.write_all(cmd.as_bytes())?;
-        self.map.remove(&key);
+        let pointer = match self.map.remove(&key) {
+            Some(pointer) => pointer,
+            None => return Err(KvStoreError::KeyNotFound { key: key_to_string(&key)? }),
+        };
+        self.uncompacted += pointer.len;
+
+        let cmd = Command::Remove { key };
+        let removal = self.append(&cmd)?;
+        self.uncompacted += removal.len;
+        self.maybe_sync()?;
+
+        self.maybe_roll_segment()?;
+        self.maybe_compact()?;
 
         Ok(())
     }
+}
 
-    fn fetch_value(&mut self, offset: u64) -> Result<String> {
-        self.file_handle.seek(SeekFrom::Start(offset))?;
-        let mut cmd = String::new();
-        let mut reader = BufReader::new(&self.file_handle);
-        reader.read_line(&mut cmd)?;
-        if let Command::Set { key: _, value } = ron::from_str(&cmd)? {
-            Ok(value)
-        } else {
-            Err(KvStoreError::UnknownError {
-                msg: "Command info not matched".to_owned(),
-            })
-        }
+impl<K, V> Drop for KvStore<K, V>
+where
+    K: Serialize + DeserializeOwned + Eq + Hash + Clone,
+    V: Serialize + DeserializeOwned,
+{
+    fn drop(&mut self) {
+        // Best-effort: a missing or stale snapshot just means the next `open` falls back to a
+        // full log replay, so failures here are not worth propagating.
+        let _ = self.save_index_snapshot();
     }
+}
 
-    fn build_map(&mut self) -> Result<()> {
-        if self.is_build {
-            return Ok(())
-        }
-        let mut cur_offset = self.file_handle.seek(SeekFrom::Start(0))?;
-        let mut reader = BufReader::new(&self.file_handle);
+/// Read a single length-prefixed record's payload bytes out of the segment it lives in
+fn read_record_payload(
+    readers: &mut HashMap<u64, BufReader<File>>,
+    pointer: LogPointer,
+) -> Result<Vec<u8>> {
+    let reader = readers.get_mut(&pointer.segment_id).ok_or_else(|| KvStoreError::UnknownError {
+        msg: format!("segment {} is missing", pointer.segment_id),
+    })?;
+    reader.seek(SeekFrom::Start(pointer.offset))?;
 
-        loop {
-            let mut cmd = String::new();
-            let offset = reader.read_line(&mut cmd)? as u64;
-            if offset == 0 {
-                break;
-            }
-            let cmd: Command = ron::from_str(&cmd)?;
-            match cmd {
-                Command::Set { key, value: _ } => {
-                    self.map.insert(key, cur_offset);
-                }
-                Command::Remove { key } => {
-                    self.map.remove(&key);
-                }
-            }
-            cur_offset += offset;
+    let mut len_buf = [0u8; LEN_PREFIX_SIZE as usize];
+    reader.read_exact(&mut len_buf)?;
+    let payload_len = u64::from_le_bytes(len_buf);
+
+    let mut payload = vec![0u8; payload_len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Best-effort rendering of a key for error messages, which are plain `String`s regardless of `K`
+fn key_to_string<K: Serialize>(key: &K) -> Result<String> {
+    ron::to_string(key).map_err(|error| KvStoreError::CommandConvertError {
+        msg: error.to_string(),
+    })
+}
+
+fn index_path(dir: &Path) -> PathBuf {
+    dir.join(INDEX_FILE_NAME)
+}
+
+fn header_path(dir: &Path) -> PathBuf {
+    dir.join(HEADER_FILE_NAME)
+}
+
+fn engine_path(dir: &Path) -> PathBuf {
+    dir.join(ENGINE_FILE_NAME)
+}
+
+/// Record that `dir` is being opened with the `engine` backend, so a later `open` of the same
+/// directory with a different engine can be rejected instead of silently corrupting the data.
+/// The first `open` for a directory writes the marker; every subsequent `open` just checks it.
+fn check_engine_marker(dir: &Path, engine: &str) -> Result<()> {
+    match fs::read_to_string(engine_path(dir)) {
+        Ok(found) if found == engine => Ok(()),
+        Ok(found) => Err(KvStoreError::EngineMismatch {
+            path: dir.display().to_string(),
+            found,
+            expected: engine.to_owned(),
+        }),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            fs::write(engine_path(dir), engine)?;
+            Ok(())
         }
+        Err(error) => Err(error.into()),
+    }
+}
 
-        self.is_build = true;
-        Ok(())
+/// Decrypt `record` if `cipher` is set, otherwise return its bytes unchanged
+fn decrypt_bytes(cipher: Option<&CipherState>, record: &[u8]) -> Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher.open(record),
+        None => Ok(record.to_vec()),
     }
+}
 
-    fn compaction(&mut self) -> Result<()> {
-        self.build_map()?;
-        let mut compacted_data: Vec<u8> = Vec::new();
-        {
-            let mut compacted_writer = BufWriter::new(&mut compacted_data);
-            let entries: Vec<(String, u64)> = self.map.iter().map(|(key, &offset)| (key.clone(), offset)).collect();
-            for (key, offset) in entries {
-                let value = self.fetch_value(offset)?;
-                let cmd = Command::Set { key, value };
-                let mut cmd = ron::to_string(&cmd)?;
-                cmd.push('\n');
-                compacted_writer.write_all(cmd.as_bytes())?;
+/// Set up the cipher for a passphrase-protected store: reuse the salt recorded in the directory's
+/// header file if one exists, otherwise pick a random salt and write a fresh header
+fn open_cipher(dir: &Path, passphrase: &str) -> Result<CipherState> {
+    let salt = match read_header(dir)? {
+        Some((cipher_suite, salt)) => {
+            if cipher_suite != CipherSuite::ChaCha20Poly1305 {
+                return Err(KvStoreError::UnknownError {
+                    msg: "store was encrypted with an unsupported cipher suite".to_owned(),
+                });
             }
+            salt
         }
-        self.file_handle.set_len(0)?;
-        self.file_handle.seek(SeekFrom::Start(0))?;
-        self.file_handle.write_all(&compacted_data)?;
-        self.is_build = false;
+        None => {
+            let mut salt = [0u8; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            write_header(dir, CipherSuite::ChaCha20Poly1305, &salt)?;
+            salt
+        }
+    };
 
-        Ok(())
+    CipherState::derive(passphrase, &salt)
+}
+
+fn write_header(dir: &Path, cipher_suite: CipherSuite, salt: &[u8; SALT_LEN]) -> Result<()> {
+    let mut bytes = Vec::with_capacity(1 + SALT_LEN);
+    bytes.push(cipher_suite as u8);
+    bytes.extend_from_slice(salt);
+    fs::write(header_path(dir), bytes)?;
+    Ok(())
+}
+
+fn read_header(dir: &Path) -> Result<Option<(CipherSuite, [u8; SALT_LEN])>> {
+    let bytes = match fs::read(header_path(dir)) {
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+
+    if bytes.len() != 1 + SALT_LEN {
+        return Err(KvStoreError::UnknownError {
+            msg: "corrupt encryption header".to_owned(),
+        });
     }
+
+    let cipher_suite = CipherSuite::from_byte(bytes[0])?;
+    let mut salt = [0u8; SALT_LEN];
+    salt.copy_from_slice(&bytes[1..]);
+    Ok(Some((cipher_suite, salt)))
+}
+
+/// Load the index snapshot for `dir`, if one exists and still describes the segment that is
+/// currently active. A mismatched active segment means the snapshot predates a compaction (or was
+/// never cleaned up), so the caller should fall back to a full log replay instead of trusting it.
+fn load_index_snapshot<K>(dir: &Path, current_segment: u64) -> Option<IndexSnapshot<K>>
+where
+    K: DeserializeOwned + Eq + Hash,
+{
+    let bytes = fs::read(index_path(dir)).ok()?;
+    let snapshot: IndexSnapshot<K> = bincode::deserialize(&bytes).ok()?;
+    if snapshot.active_segment != current_segment {
+        return None;
+    }
+    Some(snapshot)
+}
+
+fn segment_path(dir: &Path, segment_id: u64) -> PathBuf {
+    dir.join(format!("{}.log", segment_id))
+}
+
+/// Truncate `segment_id`'s file back to `len` bytes, discarding a dangling partial record left by
+/// a crash mid-append
+fn truncate_segment(dir: &Path, segment_id: u64, len: u64) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(segment_path(dir, segment_id))?;
+    file.set_len(len)?;
+    Ok(())
+}
+
+fn sorted_segment_ids(dir: &Path) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "log"))
+        .filter_map(|path| {
+            path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+        })
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
 }
@@ -0,0 +1,49 @@
+//! TCP server that exposes a `KvsEngine` over the wire
+
+use crate::common::{read_message, write_message, Request, Response};
+use crate::{KvsEngine, Result};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// Serves `set`/`get`/`remove` requests against any `KvsEngine<String, String>` over TCP, one
+/// connection at a time
+pub struct KvsServer<E: KvsEngine<String, String>> {
+    store: E,
+}
+
+impl<E: KvsEngine<String, String>> KvsServer<E> {
+    /// Wrap `store` so it can be served over the network
+    pub fn new(store: E) -> KvsServer<E> {
+        KvsServer { store }
+    }
+
+    /// Bind `addr` and serve requests; returns only if binding or accepting a connection fails
+    pub fn run(mut self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            let mut stream = stream?;
+            if let Err(error) = self.serve_one(&mut stream) {
+                let _ = write_message(&mut stream, &Response::Err(error.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    fn serve_one(&mut self, stream: &mut TcpStream) -> Result<()> {
+        let request: Request = read_message(stream)?;
+        let response = match request {
+            Request::Set { key, value } => match self.store.set(key, value) {
+                Ok(()) => Response::Ok,
+                Err(error) => Response::Err(error.to_string()),
+            },
+            Request::Get { key } => match self.store.get(key) {
+                Ok(value) => Response::Value(value),
+                Err(error) => Response::Err(error.to_string()),
+            },
+            Request::Remove { key } => match self.store.remove(key) {
+                Ok(()) => Response::Ok,
+                Err(error) => Response::Err(error.to_string()),
+            },
+        };
+        write_message(stream, &response)
+    }
+}
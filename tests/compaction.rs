@@ -0,0 +1,33 @@
+use kvs::{KvStore, KvsEngine};
+use tempfile::TempDir;
+
+/// Repeatedly overwriting a small set of keys should pile up enough stale bytes to roll the
+/// active segment and trigger compaction, while the live values stay correct both before and
+/// after a fresh `open` reloads whatever compaction left behind.
+#[test]
+fn compaction_preserves_live_data_across_segment_roll() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store: KvStore<String, String> = KvStore::open(temp_dir.path()).unwrap();
+
+    let filler = "x".repeat(256);
+    let writes = 10_000;
+    let live_keys = 5;
+    for i in 0..writes {
+        let key = format!("key-{}", i % live_keys);
+        store.set(key, format!("{}-{}", filler, i)).unwrap();
+    }
+
+    for j in 0..live_keys {
+        let key = format!("key-{}", j);
+        let expected = format!("{}-{}", filler, writes - live_keys + j);
+        assert_eq!(store.get(key).unwrap(), Some(expected));
+    }
+    drop(store);
+
+    let mut reopened: KvStore<String, String> = KvStore::open(temp_dir.path()).unwrap();
+    for j in 0..live_keys {
+        let key = format!("key-{}", j);
+        let expected = format!("{}-{}", filler, writes - live_keys + j);
+        assert_eq!(reopened.get(key).unwrap(), Some(expected));
+    }
+}
@@ -0,0 +1,30 @@
+use kvs::{KvStore, KvStoreError, KvStoreOptions, KvsEngine};
+use tempfile::TempDir;
+
+/// A store opened with a passphrase must be reopenable with the same passphrase, and reopening it
+/// with no passphrase at all must be rejected instead of silently falling back to writing
+/// plaintext into an otherwise-encrypted log.
+#[test]
+fn reopening_encrypted_store_requires_same_passphrase() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store: KvStore<String, String> = KvStoreOptions::new()
+        .passphrase("hunter2")
+        .open(temp_dir.path())
+        .unwrap();
+    store.set("secret".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+
+    let mut reopened: KvStore<String, String> = KvStoreOptions::new()
+        .passphrase("hunter2")
+        .open(temp_dir.path())
+        .unwrap();
+    assert_eq!(
+        reopened.get("secret".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    drop(reopened);
+
+    let result = KvStore::<String, String>::open(temp_dir.path());
+    assert!(matches!(result, Err(KvStoreError::PassphraseRequired { .. })));
+}
@@ -0,0 +1,33 @@
+use kvs::{KvStore, KvStoreError, KvsClient, KvsServer};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// `set`/`get`/`remove` issued by `KvsClient` against a `KvsServer` over TCP must see the same
+/// results a caller would get from the underlying store directly, including a `KeyNotFound` error
+/// on a second `remove` of the same key.
+#[test]
+fn set_get_remove_round_trip_over_tcp() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:14099";
+
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    assert_eq!(KvsClient::get(addr, "key".to_owned()).unwrap(), None);
+
+    KvsClient::set(addr, "key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(
+        KvsClient::get(addr, "key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+
+    KvsClient::remove(addr, "key".to_owned()).unwrap();
+    assert_eq!(KvsClient::get(addr, "key".to_owned()).unwrap(), None);
+
+    let error = KvsClient::remove(addr, "key".to_owned()).unwrap_err();
+    assert!(matches!(error, KvStoreError::KeyNotFound { .. }));
+}
@@ -0,0 +1,22 @@
+use kvs::{KvStore, KvsEngine};
+use tempfile::TempDir;
+
+/// Opening a store and dropping it again without touching it must not overwrite a good index
+/// snapshot with an empty, never-built map: that would make every key written earlier
+/// permanently invisible on the next `open`.
+#[test]
+fn reopen_after_noop_open_and_drop_preserves_data() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store: KvStore<String, String> = KvStore::open(temp_dir.path()).unwrap();
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    store.set("b".to_owned(), "2".to_owned()).unwrap();
+    drop(store);
+
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path()).unwrap();
+    drop(store);
+
+    let mut store: KvStore<String, String> = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+}